@@ -14,8 +14,10 @@
 // To conserve gas, efficient serialization is achieved through Borsh (http://borsh.io/)
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::wee_alloc;
-use near_sdk::{env, near_bindgen, ext_contract, Promise, PromiseResult, Gas};
-use near_sdk::json_types::{ValidAccountId, U128};
+use near_sdk::{env, near_bindgen, ext_contract, AccountId, Promise, PromiseResult, Gas};
+use near_sdk::json_types::{Base64VecU8, ValidAccountId, U128};
+use near_sdk::collections::LookupMap;
+use near_sdk::serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use near_sdk::serde_json::{self, json};
 
@@ -25,25 +27,82 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 // Structs in Rust are similar to other languages, and may include impl keyword as shown below
 // Note: the names of the structs are not important when calling the smart contract, but the function names are
 #[near_bindgen]
-#[derive(Default, BorshDeserialize, BorshSerialize)]
+#[derive(BorshDeserialize, BorshSerialize)]
 pub struct Welcome {
     records: HashMap<String, String>,
+    /// Cached `(token_id, account_id)` balances along with the block index they were taken at
+    snapshots: LookupMap<(AccountId, AccountId), (U128, u64)>,
+}
+
+/// Storage prefixes of the trie-backed collections, one variant per collection so that no two
+/// collections can share a key prefix. Only append new variants, as the index is the prefix.
+#[derive(BorshSerialize)]
+enum StorageKey {
+    Snapshots,
+}
+
+impl StorageKey {
+    fn into_prefix(self) -> Vec<u8> {
+        self.try_to_vec().unwrap()
+    }
+}
+
+impl Default for Welcome {
+    fn default() -> Self {
+        Self {
+            records: HashMap::new(),
+            snapshots: LookupMap::new(StorageKey::Snapshots.into_prefix()),
+        }
+    }
 }
 
 const GAS_BASE_COMPUTE: Gas = 5_000_000_000_000;
 /// Indicates there are no deposit for a callback for better readability.
 const NO_DEPOSIT: u128 = 0;
+/// Maximum number of `ft_balance_of` calls fanned out by a single batch query.
+const MAX_BATCH_SIZE: usize = 20;
+/// Extra gas the snapshot callback needs for every entry it writes.
+const GAS_PER_SNAPSHOT_WRITE: Gas = 1_000_000_000_000;
+/// Price of one byte of storage, 1 NEAR per 100kb.
+const STORAGE_PRICE_PER_BYTE: u128 = 10_000_000_000_000_000_000;
+/// Storage of one new snapshot entry with 64-character account ids: 40 bytes of trie record
+/// overhead, the 1-byte prefix, two Borsh strings (4 + 64 bytes each) and the `(U128, u64)` value.
+const SNAPSHOT_ENTRY_MAX_BYTES: u128 = 40 + 1 + 2 * (4 + 64) + 16 + 8;
 
 #[ext_contract(ext_self)]
 pub trait ExtDemo {
     /// Callback after receiving balances
     fn on_get_balance(&self) -> bool;
+    /// Callback after receiving a batch of balances
+    fn on_get_balances(&self) -> Vec<U128>;
+    /// Callback after receiving a deduplicated batch of token balances
+    fn on_get_ft_balances(&self, result_indices: Vec<u64>) -> Vec<U128>;
+    /// Callback after receiving a batch of balances to be stored as snapshots
+    fn on_snapshot_resolved(
+        &mut self,
+        token_id: AccountId,
+        account_ids: Vec<AccountId>,
+        payer_id: AccountId,
+        deposit: U128,
+    );
+    /// Callback after receiving the contract's storage balance on a token
+    fn on_storage_balance_of(&self) -> bool;
 }
 
 #[ext_contract(ext_fungible_token)]
 pub trait FungibleTokenContract {
     /// Returns the balance of the account. If the account doesn't exist must returns `"0"`.
     fn ft_balance_of(&self, account_id: AccountId) -> U128;
+    /// Returns the storage balance of the account, or `null` if it isn't registered.
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
+}
+
+/// Storage balance of an account as returned by a NEP-145 `storage_balance_of` call.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
 }
 
 fn get_promise_result() -> U128 {
@@ -58,9 +117,79 @@ fn get_promise_result() -> U128 {
     }
 }
 
+/// Rejects the call up front when it couldn't pay for all of its stages anyway. `promise_gas` is
+/// the gas attached to the outgoing promises; a `GAS_BASE_COMPUTE` reserve is added on top for
+/// the current call itself.
+fn assert_min_gas(promise_gas: Gas) {
+    let required = promise_gas + GAS_BASE_COMPUTE;
+    assert!(
+        env::prepaid_gas() >= required,
+        "Not enough gas attached, expected at least {}",
+        required
+    );
+}
+
+/// Deposit covering the storage of `count` new snapshot entries in the worst case.
+fn snapshot_deposit(count: usize) -> u128 {
+    count as u128 * SNAPSHOT_ENTRY_MAX_BYTES * STORAGE_PRICE_PER_BYTE
+}
+
+/// Gas the snapshot callback needs to write `count` entries and, should the deposit fall short,
+/// put the previous entries back.
+fn snapshot_write_gas(count: usize) -> Gas {
+    2 * count as Gas * GAS_PER_SNAPSHOT_WRITE
+}
+
+/// Gas for the snapshot callback to write `count` entries and refund the unused deposit.
+fn snapshot_callback_gas(count: usize) -> Gas {
+    GAS_BASE_COMPUTE + snapshot_write_gas(count)
+}
+
+/// Returns `amount` of a deposit to `payer_id`, if there is anything to return.
+fn refund_deposit(payer_id: AccountId, amount: u128) {
+    if amount > 0 {
+        Promise::new(payer_id).transfer(amount);
+    }
+}
+
+/// Collects every joined promise result in attachment order, with `None` for a failed or
+/// unparseable leg.
+fn get_promise_results() -> Vec<Option<U128>> {
+    (0..env::promise_results_count())
+        .map(|i| match env::promise_result(i) {
+            PromiseResult::Successful(x) => serde_json::from_slice::<U128>(&x).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Joins one `ft_balance_of` call per `(token_id, account_id)` query. The joined promise results
+/// come back in the same order the queries are given here.
+fn ft_balances_of(queries: Vec<(&AccountId, &AccountId)>) -> Promise {
+    assert!(!queries.is_empty(), "Expected at least one balance query");
+    assert!(
+        queries.len() <= MAX_BATCH_SIZE,
+        "Too many balance queries, the maximum is {}",
+        MAX_BATCH_SIZE
+    );
+    // one call per query plus the callback
+    assert_min_gas(GAS_BASE_COMPUTE * (queries.len() as Gas + 1));
+    queries
+        .into_iter()
+        .map(|(token_id, account_id)| ext_fungible_token::ft_balance_of(
+            account_id.clone(),
+            token_id,
+            NO_DEPOSIT,
+            GAS_BASE_COMPUTE
+        ))
+        .reduce(|acc, promise| acc.and(promise))
+        .unwrap()
+}
+
 #[near_bindgen]
 impl Welcome {
     pub fn get_ft_balance1(&self, contract_id: ValidAccountId, account_id: ValidAccountId) -> Promise {
+        assert_min_gas(2 * GAS_BASE_COMPUTE);
         Promise::new(contract_id.as_ref().clone())
             .function_call(
                 b"ft_balance_of".to_vec(), 
@@ -75,6 +204,7 @@ impl Welcome {
     }
 
     pub fn get_ft_balance2(&self, contract_id: ValidAccountId, account_id: ValidAccountId) -> Promise {
+        assert_min_gas(2 * GAS_BASE_COMPUTE);
         ext_fungible_token::ft_balance_of(
             account_id.as_ref().clone(),
             contract_id.as_ref(),
@@ -96,6 +226,221 @@ impl Welcome {
         let balance = get_promise_result();
         env::log(format!("The received balance is {}", balance.0).as_bytes());
     }
+
+    /// Queries the balances of many accounts for one token, e.g. for airdrops or snapshots.
+    /// Resolves into the balances in the same order as `account_ids`.
+    pub fn get_balances_for_accounts(&self, token_id: ValidAccountId, account_ids: Vec<ValidAccountId>) -> Promise {
+        let queries = account_ids.iter().map(|account_id| (token_id.as_ref(), account_id.as_ref())).collect();
+        ft_balances_of(queries).then(ext_self::on_get_balances(
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_BASE_COMPUTE,
+        ))
+    }
+
+    /// Queries the balances of one account across many tokens.
+    /// Resolves into the balances in the same order as `contract_ids`. A token listed more than
+    /// once is only queried once, and its balance is repeated at each position it was asked for.
+    /// The request itself, duplicates included, is capped at `MAX_BATCH_SIZE` tokens.
+    pub fn get_ft_balances(&self, contract_ids: Vec<ValidAccountId>, account_id: ValidAccountId) -> Promise {
+        assert!(
+            contract_ids.len() <= MAX_BATCH_SIZE,
+            "Too many balance queries, the maximum is {}",
+            MAX_BATCH_SIZE
+        );
+        // the query index answering each requested token, in first-seen order
+        let mut unique_ids: Vec<&AccountId> = vec![];
+        let result_indices = contract_ids
+            .iter()
+            .map(|contract_id| {
+                let contract_id = contract_id.as_ref();
+                let index = match unique_ids.iter().position(|id| *id == contract_id) {
+                    Some(index) => index,
+                    None => {
+                        unique_ids.push(contract_id);
+                        unique_ids.len() - 1
+                    }
+                };
+                index as u64
+            })
+            .collect();
+        let queries = unique_ids.into_iter().map(|contract_id| (contract_id, account_id.as_ref())).collect();
+        ft_balances_of(queries).then(ext_self::on_get_ft_balances(
+            result_indices,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_BASE_COMPUTE,
+        ))
+    }
+
+    /// Maps the deduplicated balances back onto the shape of the original `get_ft_balances` request.
+    pub fn on_get_ft_balances(&self, result_indices: Vec<u64>) -> Vec<U128> {
+        let balances = self.on_get_balances();
+        result_indices
+            .into_iter()
+            .map(|index| *balances.get(index as usize).expect("Contract expected a result for every token"))
+            .collect()
+    }
+
+    /// Promise results are indexed by the order the joined promises were attached, so the returned
+    /// balances line up index-for-index with the batch that was requested.
+    pub fn on_get_balances(&self) -> Vec<U128> {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Callback can only be called from the contract"
+        );
+        get_promise_results().into_iter().map(|balance| balance.unwrap_or(U128(0))).collect()
+    }
+
+    /// Queries the balances of many accounts for one token and caches them, see `get_snapshot`.
+    /// The caller pays for the snapshot storage: the attached deposit must cover a worst-case entry
+    /// per account, and whatever the new entries don't use is refunded by the callback.
+    #[payable]
+    pub fn snapshot_balances(&mut self, token_id: ValidAccountId, account_ids: Vec<ValidAccountId>) -> Promise {
+        let required_deposit = snapshot_deposit(account_ids.len());
+        assert!(
+            env::attached_deposit() >= required_deposit,
+            "Not enough deposit attached to cover the snapshot storage, expected at least {}",
+            required_deposit
+        );
+        let callback_gas = snapshot_callback_gas(account_ids.len());
+        assert_min_gas(GAS_BASE_COMPUTE * account_ids.len() as Gas + callback_gas);
+        let queries = account_ids.iter().map(|account_id| (token_id.as_ref(), account_id.as_ref())).collect();
+        ft_balances_of(queries).then(ext_self::on_snapshot_resolved(
+            token_id.as_ref().clone(),
+            account_ids.iter().map(|account_id| account_id.as_ref().clone()).collect(),
+            env::predecessor_account_id(),
+            U128(env::attached_deposit()),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            callback_gas,
+        ))
+    }
+
+    /// Stores each received balance as a snapshot. Failed legs leave the previous snapshot as is.
+    /// The storage the new entries take is charged against `deposit`, and the rest goes back to
+    /// `payer_id`. This never panics once the deposit is held: if the snapshot can't be stored,
+    /// the error is logged, no entries change and the whole deposit is refunded.
+    pub fn on_snapshot_resolved(
+        &mut self,
+        token_id: AccountId,
+        account_ids: Vec<AccountId>,
+        payer_id: AccountId,
+        deposit: U128,
+    ) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Callback can only be called from the contract"
+        );
+        let balances = get_promise_results();
+        if balances.len() != account_ids.len() {
+            env::log(b"Contract expected a result for every account, the snapshot is skipped");
+            refund_deposit(payer_id, deposit.0);
+            return;
+        }
+        if env::prepaid_gas().saturating_sub(env::used_gas()) < snapshot_write_gas(account_ids.len()) {
+            env::log(b"Not enough gas left to store the snapshot, the snapshot is skipped");
+            refund_deposit(payer_id, deposit.0);
+            return;
+        }
+        let initial_storage_usage = env::storage_usage();
+        let block_index = env::block_index();
+        let mut previous_entries = vec![];
+        for (account_id, balance) in account_ids.into_iter().zip(balances) {
+            if let Some(balance) = balance {
+                let key = (token_id.clone(), account_id);
+                let previous = self.snapshots.insert(&key, &(balance, block_index));
+                previous_entries.push((key, previous));
+            }
+        }
+        let storage_cost =
+            env::storage_usage().saturating_sub(initial_storage_usage) as u128 * STORAGE_PRICE_PER_BYTE;
+        if storage_cost > deposit.0 {
+            for (key, previous) in previous_entries {
+                match previous {
+                    Some(previous) => self.snapshots.insert(&key, &previous),
+                    None => self.snapshots.remove(&key),
+                };
+            }
+            env::log(
+                format!(
+                    "Not enough deposit attached to cover the snapshot storage, expected at least {}, the snapshot is skipped",
+                    storage_cost
+                )
+                .as_bytes(),
+            );
+            refund_deposit(payer_id, deposit.0);
+            return;
+        }
+        refund_deposit(payer_id, deposit.0 - storage_cost);
+    }
+
+    /// Checks whether this contract is registered with the storage of `token_id`, which tells
+    /// clients if the token can be transferred to it.
+    pub fn is_self_registered(&self, token_id: ValidAccountId) -> Promise {
+        assert_min_gas(2 * GAS_BASE_COMPUTE);
+        ext_fungible_token::storage_balance_of(
+            env::current_account_id(),
+            token_id.as_ref(),
+            NO_DEPOSIT,
+            GAS_BASE_COMPUTE
+        ).then(ext_self::on_storage_balance_of(
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_BASE_COMPUTE,
+        ))
+    }
+
+    pub fn on_storage_balance_of(&self) -> bool {
+        assert_eq!(
+            env::predecessor_account_id(),
+            env::current_account_id(),
+            "Callback can only be called from the contract"
+        );
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "Contract expected a result on the callback"
+        );
+        match env::promise_result(0) {
+            PromiseResult::Successful(x) => serde_json::from_slice::<Option<StorageBalance>>(&x)
+                .unwrap_or(None)
+                .is_some(),
+            _ => panic!("Promise was not successful")
+        }
+    }
+
+    /// Returns the UTF-8 bytes of the greeting saved for `account_id`, defaulting to "Hello",
+    /// for clients that prefer raw bytes over a JSON string.
+    pub fn get_greeting_bytes(&self, account_id: ValidAccountId) -> Base64VecU8 {
+        let greeting = self.records.get(account_id.as_ref()).map(String::as_str).unwrap_or("Hello");
+        Base64VecU8(greeting.as_bytes().to_vec())
+    }
+
+    /// Returns a hash-chain commitment over all greetings so light clients can verify them.
+    ///
+    /// Starting from 32 zero bytes, for each `(account_id, message)` pair in ascending byte order of
+    /// `account_id` the root becomes `sha256(root || borsh((account_id, message)))`, where Borsh
+    /// encodes each string as its little-endian `u32` length followed by its UTF-8 bytes. With no
+    /// greetings the root is the 32 zero bytes.
+    pub fn greetings_root(&self) -> Base64VecU8 {
+        let mut records: Vec<(&String, &String)> = self.records.iter().collect();
+        records.sort();
+        let root = records.into_iter().fold(vec![0u8; 32], |root, (account_id, message)| {
+            let mut preimage = root;
+            preimage.extend(account_id.try_to_vec().unwrap());
+            preimage.extend(message.try_to_vec().unwrap());
+            env::sha256(&preimage)
+        });
+        Base64VecU8(root)
+    }
+
+    /// Returns the cached balance of `account_id` for `token_id` and the block index it was taken at.
+    pub fn get_snapshot(&self, token_id: ValidAccountId, account_id: ValidAccountId) -> Option<(U128, u64)> {
+        self.snapshots.get(&(token_id.as_ref().clone(), account_id.as_ref().clone()))
+    }
 }
 
 /*
@@ -114,6 +459,7 @@ mod tests {
     use super::*;
     use near_sdk::MockedBlockchain;
     use near_sdk::{testing_env, VMContext};
+    use std::convert::TryFrom;
 
     // mock the context for testing, notice "signer_account_id" that was accessed above from env::
     fn get_context(input: Vec<u8>, is_view: bool) -> VMContext {
@@ -136,4 +482,499 @@ mod tests {
             epoch_height: 19,
         }
     }
+
+    // install a mocked blockchain whose callback sees the given promise results
+    fn set_context_with_results(context: VMContext, promise_results: Vec<PromiseResult>) {
+        let storage = match env::take_blockchain_interface() {
+            Some(mut bi) => bi.as_mut_mocked_blockchain().unwrap().take_storage(),
+            None => Default::default(),
+        };
+        env::set_blockchain_interface(Box::new(MockedBlockchain::new(
+            context,
+            Default::default(),
+            Default::default(),
+            promise_results,
+            storage,
+            Default::default(),
+        )));
+    }
+
+    // context for a callback, which the contract calls on itself
+    fn get_callback_context() -> VMContext {
+        let mut context = get_context(vec![], false);
+        context.predecessor_account_id = context.current_account_id.clone();
+        // enough to refund the deposits the callbacks are handed
+        context.account_balance = 10u128.pow(25);
+        context
+    }
+
+    fn balance_result(balance: u128) -> PromiseResult {
+        PromiseResult::Successful(serde_json::to_vec(&U128(balance)).unwrap())
+    }
+
+    fn accounts(n: usize) -> Vec<ValidAccountId> {
+        (0..n).map(|i| ValidAccountId::try_from(format!("account{}.near", i)).unwrap()).collect()
+    }
+
+    #[test]
+    fn get_balances_for_accounts_fans_out_one_call_per_account() {
+        testing_env!(get_context(vec![], false));
+        let contract = Welcome::default();
+        contract.get_balances_for_accounts(ValidAccountId::try_from("token.near").unwrap(), accounts(3));
+        let receipts: Vec<String> = env::created_receipts().iter().map(|r| format!("{:?}", r)).collect();
+        // three balance queries plus the callback
+        assert_eq!(receipts.len(), 4);
+        for (i, receipt) in receipts[..3].iter().enumerate() {
+            assert!(receipt.contains("receiver_id: \"token.near\""));
+            let args = format!("{{\"account_id\":\"account{}.near\"}}", i);
+            assert!(receipt.contains(&format!("{:?}", args.as_bytes())));
+        }
+        assert!(receipts[3].contains("receiver_id: \"alice_near\""));
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many balance queries, the maximum is 20")]
+    fn get_balances_for_accounts_rejects_too_many_accounts() {
+        testing_env!(get_context(vec![], false));
+        let contract = Welcome::default();
+        contract.get_balances_for_accounts(ValidAccountId::try_from("token.near").unwrap(), accounts(MAX_BATCH_SIZE + 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas attached, expected at least 15000000000000")]
+    fn get_ft_balance1_rejects_low_prepaid_gas() {
+        let mut context = get_context(vec![], false);
+        // enough for both promises, but not for the call attaching them
+        context.prepaid_gas = 2 * GAS_BASE_COMPUTE;
+        testing_env!(context);
+        let contract = Welcome::default();
+        contract.get_ft_balance1(
+            ValidAccountId::try_from("token.near").unwrap(),
+            ValidAccountId::try_from("bob_near").unwrap(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas attached, expected at least 25000000000000")]
+    fn get_balances_for_accounts_rejects_low_prepaid_gas() {
+        let mut context = get_context(vec![], false);
+        context.prepaid_gas = 4 * GAS_BASE_COMPUTE;
+        testing_env!(context);
+        let contract = Welcome::default();
+        contract.get_balances_for_accounts(ValidAccountId::try_from("token.near").unwrap(), accounts(3));
+    }
+
+    #[test]
+    fn on_get_balances_treats_failed_legs_as_zero() {
+        set_context_with_results(
+            get_callback_context(),
+            vec![balance_result(10), PromiseResult::Failed, balance_result(30), PromiseResult::Successful(b"oops".to_vec())],
+        );
+        let contract = Welcome::default();
+        let balances: Vec<u128> = contract.on_get_balances().into_iter().map(|b| b.0).collect();
+        assert_eq!(balances, vec![10, 0, 30, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Callback can only be called from the contract")]
+    fn on_get_balances_rejects_external_callers() {
+        set_context_with_results(get_context(vec![], false), vec![balance_result(10)]);
+        let contract = Welcome::default();
+        contract.on_get_balances();
+    }
+
+    #[test]
+    fn get_ft_balances_queries_tokens_in_input_order() {
+        testing_env!(get_context(vec![], false));
+        let contract = Welcome::default();
+        let contract_ids: Vec<ValidAccountId> = ["usdc.near", "dai.near", "wnear.near"]
+            .iter()
+            .map(|id| ValidAccountId::try_from(*id).unwrap())
+            .collect();
+        contract.get_ft_balances(contract_ids, ValidAccountId::try_from("bob_near").unwrap());
+        let receipts: Vec<String> = env::created_receipts().iter().map(|r| format!("{:?}", r)).collect();
+        assert_eq!(receipts.len(), 4);
+        assert!(receipts[0].contains("receiver_id: \"usdc.near\""));
+        assert!(receipts[1].contains("receiver_id: \"dai.near\""));
+        assert!(receipts[2].contains("receiver_id: \"wnear.near\""));
+    }
+
+    #[test]
+    fn get_ft_balances_queries_duplicate_tokens_once() {
+        testing_env!(get_context(vec![], false));
+        let contract = Welcome::default();
+        let contract_ids: Vec<ValidAccountId> = ["usdc.near", "dai.near", "usdc.near", "wnear.near", "dai.near"]
+            .iter()
+            .map(|id| ValidAccountId::try_from(*id).unwrap())
+            .collect();
+        contract.get_ft_balances(contract_ids, ValidAccountId::try_from("bob_near").unwrap());
+        let receipts: Vec<String> = env::created_receipts().iter().map(|r| format!("{:?}", r)).collect();
+        // three distinct tokens plus the callback
+        assert_eq!(receipts.len(), 4);
+        assert!(receipts[0].contains("receiver_id: \"usdc.near\""));
+        assert!(receipts[1].contains("receiver_id: \"dai.near\""));
+        assert!(receipts[2].contains("receiver_id: \"wnear.near\""));
+        let callback_args = "{\"result_indices\":[0,1,0,2,1]}";
+        assert!(receipts[3].contains(&format!("{:?}", callback_args.as_bytes())));
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many balance queries, the maximum is 20")]
+    fn get_ft_balances_rejects_too_many_duplicate_tokens() {
+        testing_env!(get_context(vec![], false));
+        let contract = Welcome::default();
+        let contract_ids: Vec<ValidAccountId> = (0..MAX_BATCH_SIZE + 1)
+            .map(|i| ValidAccountId::try_from(if i % 2 == 0 { "usdc.near" } else { "dai.near" }).unwrap())
+            .collect();
+        contract.get_ft_balances(contract_ids, ValidAccountId::try_from("bob_near").unwrap());
+    }
+
+    #[test]
+    fn on_get_ft_balances_maps_results_back_to_request() {
+        set_context_with_results(
+            get_callback_context(),
+            vec![balance_result(100), balance_result(200), PromiseResult::Failed],
+        );
+        let contract = Welcome::default();
+        let balances: Vec<u128> = contract
+            .on_get_ft_balances(vec![0, 1, 0, 2, 1])
+            .into_iter()
+            .map(|b| b.0)
+            .collect();
+        assert_eq!(balances, vec![100, 200, 100, 0, 200]);
+    }
+
+    #[test]
+    fn on_get_balances_preserves_result_order() {
+        set_context_with_results(
+            get_callback_context(),
+            vec![balance_result(300), balance_result(100), balance_result(200)],
+        );
+        let contract = Welcome::default();
+        let balances: Vec<u128> = contract.on_get_balances().into_iter().map(|b| b.0).collect();
+        assert_eq!(balances, vec![300, 100, 200]);
+    }
+
+    #[test]
+    fn on_snapshot_resolved_stores_balances_with_block_index() {
+        let mut context = get_callback_context();
+        context.block_index = 42;
+        set_context_with_results(context, vec![balance_result(10), balance_result(20)]);
+        let mut contract = Welcome::default();
+        contract.on_snapshot_resolved(
+            "token.near".to_string(),
+            vec!["account0.near".to_string(), "account1.near".to_string()],
+            "bob_near".to_string(),
+            U128(snapshot_deposit(2)),
+        );
+        let token = ValidAccountId::try_from("token.near").unwrap();
+        let accounts = accounts(2);
+        assert_eq!(contract.get_snapshot(token.clone(), accounts[0].clone()), Some((U128(10), 42)));
+        assert_eq!(contract.get_snapshot(token, accounts[1].clone()), Some((U128(20), 42)));
+    }
+
+    #[test]
+    fn on_snapshot_resolved_keeps_previous_snapshot_on_failed_leg() {
+        let mut context = get_callback_context();
+        context.block_index = 1;
+        set_context_with_results(context, vec![balance_result(10), balance_result(20)]);
+        let mut contract = Welcome::default();
+        let account_ids = vec!["account0.near".to_string(), "account1.near".to_string()];
+        contract.on_snapshot_resolved(
+            "token.near".to_string(),
+            account_ids.clone(),
+            "bob_near".to_string(),
+            U128(snapshot_deposit(2)),
+        );
+
+        let mut context = get_callback_context();
+        context.block_index = 2;
+        context.storage_usage = env::storage_usage();
+        set_context_with_results(context, vec![PromiseResult::Failed, balance_result(25)]);
+        contract.on_snapshot_resolved(
+            "token.near".to_string(),
+            account_ids,
+            "bob_near".to_string(),
+            U128(snapshot_deposit(2)),
+        );
+
+        let token = ValidAccountId::try_from("token.near").unwrap();
+        let accounts = accounts(2);
+        assert_eq!(contract.get_snapshot(token.clone(), accounts[0].clone()), Some((U128(10), 1)));
+        assert_eq!(contract.get_snapshot(token, accounts[1].clone()), Some((U128(25), 2)));
+    }
+
+    // a 64-character account id, the longest allowed
+    fn long_account(i: usize) -> AccountId {
+        format!("{:0>59}.near", i)
+    }
+
+    #[test]
+    fn snapshot_balances_passes_payer_and_deposit_to_callback() {
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = snapshot_deposit(2);
+        testing_env!(context);
+        let mut contract = Welcome::default();
+        contract.snapshot_balances(ValidAccountId::try_from("token.near").unwrap(), accounts(2));
+        let receipts: Vec<String> = env::created_receipts().iter().map(|r| format!("{:?}", r)).collect();
+        assert_eq!(receipts.len(), 3);
+        let callback_args = format!(
+            "{{\"token_id\":\"token.near\",\"account_ids\":[\"account0.near\",\"account1.near\"],\"payer_id\":\"carol_near\",\"deposit\":\"{}\"}}",
+            snapshot_deposit(2)
+        );
+        assert!(receipts[2].contains(&format!("{:?}", callback_args.as_bytes())));
+        assert!(receipts[2].contains(&format!("gas: {}", snapshot_callback_gas(2))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough deposit attached to cover the snapshot storage")]
+    fn snapshot_balances_rejects_insufficient_deposit() {
+        let mut context = get_context(vec![], false);
+        context.attached_deposit = snapshot_deposit(2) - 1;
+        testing_env!(context);
+        let mut contract = Welcome::default();
+        contract.snapshot_balances(ValidAccountId::try_from("token.near").unwrap(), accounts(2));
+    }
+
+    #[test]
+    fn on_snapshot_resolved_refunds_unused_deposit() {
+        set_context_with_results(get_callback_context(), vec![balance_result(10), PromiseResult::Failed]);
+        let mut contract = Welcome::default();
+        let deposit = snapshot_deposit(2);
+        contract.on_snapshot_resolved(
+            "token.near".to_string(),
+            vec!["account0.near".to_string(), "account1.near".to_string()],
+            "bob_near".to_string(),
+            U128(deposit),
+        );
+        // only one entry was written, and its storage is all that is kept
+        let storage_cost = env::storage_usage() as u128 * STORAGE_PRICE_PER_BYTE;
+        assert!(storage_cost > 0);
+        let receipts: Vec<String> = env::created_receipts().iter().map(|r| format!("{:?}", r)).collect();
+        assert_eq!(receipts.len(), 1);
+        assert!(receipts[0].contains("receiver_id: \"bob_near\""));
+        assert!(receipts[0].contains(&format!("deposit: {}", deposit - storage_cost)));
+    }
+
+    // the receipts created by the callback, i.e. its refunds
+    fn refund_receipts() -> Vec<String> {
+        env::created_receipts().iter().map(|r| format!("{:?}", r)).collect()
+    }
+
+    #[test]
+    fn on_snapshot_resolved_refunds_everything_on_insufficient_deposit() {
+        let mut context = get_callback_context();
+        context.block_index = 1;
+        set_context_with_results(context, vec![balance_result(10)]);
+        let mut contract = Welcome::default();
+        contract.on_snapshot_resolved(
+            "token.near".to_string(),
+            vec!["account0.near".to_string()],
+            "bob_near".to_string(),
+            U128(snapshot_deposit(1)),
+        );
+
+        // the second call adds a new entry it can't pay for and overwrites an existing one
+        let mut context = get_callback_context();
+        context.block_index = 2;
+        let storage_usage = env::storage_usage();
+        context.storage_usage = storage_usage;
+        set_context_with_results(context, vec![balance_result(20), balance_result(30)]);
+        contract.on_snapshot_resolved(
+            "token.near".to_string(),
+            vec!["account0.near".to_string(), "account1.near".to_string()],
+            "bob_near".to_string(),
+            U128(1),
+        );
+        assert_eq!(env::storage_usage(), storage_usage);
+
+        let token = ValidAccountId::try_from("token.near").unwrap();
+        let accounts = accounts(2);
+        assert_eq!(contract.get_snapshot(token.clone(), accounts[0].clone()), Some((U128(10), 1)));
+        assert_eq!(contract.get_snapshot(token, accounts[1].clone()), None);
+        let receipts = refund_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert!(receipts[0].contains("receiver_id: \"bob_near\""));
+        assert!(receipts[0].contains("deposit: 1 "));
+    }
+
+    #[test]
+    fn on_snapshot_resolved_refunds_everything_on_missing_results() {
+        set_context_with_results(get_callback_context(), vec![balance_result(10)]);
+        let mut contract = Welcome::default();
+        let deposit = snapshot_deposit(2);
+        contract.on_snapshot_resolved(
+            "token.near".to_string(),
+            vec!["account0.near".to_string(), "account1.near".to_string()],
+            "bob_near".to_string(),
+            U128(deposit),
+        );
+        let token = ValidAccountId::try_from("token.near").unwrap();
+        assert_eq!(contract.get_snapshot(token, accounts(1)[0].clone()), None);
+        let receipts = refund_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert!(receipts[0].contains(&format!("deposit: {} ", deposit)));
+    }
+
+    #[test]
+    fn on_snapshot_resolved_refunds_everything_on_low_gas() {
+        let mut context = get_callback_context();
+        context.prepaid_gas = snapshot_write_gas(2) - 1;
+        set_context_with_results(context, vec![balance_result(10), balance_result(20)]);
+        let mut contract = Welcome::default();
+        let deposit = snapshot_deposit(2);
+        contract.on_snapshot_resolved(
+            "token.near".to_string(),
+            vec!["account0.near".to_string(), "account1.near".to_string()],
+            "bob_near".to_string(),
+            U128(deposit),
+        );
+        let token = ValidAccountId::try_from("token.near").unwrap();
+        assert_eq!(contract.get_snapshot(token, accounts(1)[0].clone()), None);
+        let receipts = refund_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert!(receipts[0].contains(&format!("deposit: {} ", deposit)));
+    }
+
+    #[test]
+    fn on_snapshot_resolved_full_batch_fits_in_callback_gas() {
+        let mut context = get_callback_context();
+        context.prepaid_gas = snapshot_callback_gas(MAX_BATCH_SIZE);
+        set_context_with_results(context, (0..MAX_BATCH_SIZE).map(|_| balance_result(u128::MAX)).collect());
+        let mut contract = Welcome::default();
+        let token_id = long_account(MAX_BATCH_SIZE);
+        let account_ids: Vec<AccountId> = (0..MAX_BATCH_SIZE).map(long_account).collect();
+        contract.on_snapshot_resolved(
+            token_id.clone(),
+            account_ids.clone(),
+            "bob_near".to_string(),
+            U128(snapshot_deposit(MAX_BATCH_SIZE)),
+        );
+        // every entry took at most the worst case the deposit was sized for
+        assert_eq!(env::storage_usage() as u128, MAX_BATCH_SIZE as u128 * SNAPSHOT_ENTRY_MAX_BYTES);
+        let token = ValidAccountId::try_from(token_id).unwrap();
+        for account_id in account_ids {
+            let account = ValidAccountId::try_from(account_id).unwrap();
+            assert!(contract.get_snapshot(token.clone(), account).is_some());
+        }
+    }
+
+    #[test]
+    fn get_snapshot_serializes_large_balance_as_string() {
+        let balance = u128::MAX - 1;
+        set_context_with_results(get_callback_context(), vec![balance_result(balance)]);
+        let mut contract = Welcome::default();
+        contract.on_snapshot_resolved(
+            "token.near".to_string(),
+            vec!["account0.near".to_string()],
+            "bob_near".to_string(),
+            U128(snapshot_deposit(1)),
+        );
+        let snapshot = contract.get_snapshot(ValidAccountId::try_from("token.near").unwrap(), accounts(1)[0].clone());
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(json, format!("[\"{}\",0]", balance));
+        let (restored, _): (U128, u64) = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.0, balance);
+    }
+
+    #[test]
+    fn storage_key_prefixes_do_not_collide() {
+        // the exhaustive match makes a new variant fail to compile until it is listed here
+        let prefixes: Vec<Vec<u8>> = vec![StorageKey::Snapshots]
+            .into_iter()
+            .map(|key| match key {
+                StorageKey::Snapshots => key.into_prefix(),
+            })
+            .collect();
+        // the key near-sdk stores the contract struct under
+        let state_key = b"STATE".to_vec();
+        for (i, prefix) in prefixes.iter().enumerate() {
+            assert!(!state_key.starts_with(prefix) && !prefix.starts_with(&state_key));
+            for other in &prefixes[i + 1..] {
+                assert!(!other.starts_with(prefix) && !prefix.starts_with(other));
+            }
+        }
+    }
+
+    #[test]
+    fn get_snapshot_returns_none_for_unknown_account() {
+        testing_env!(get_context(vec![], true));
+        let contract = Welcome::default();
+        let token = ValidAccountId::try_from("token.near").unwrap();
+        assert_eq!(contract.get_snapshot(token, accounts(1)[0].clone()), None);
+    }
+
+    #[test]
+    fn get_greeting_bytes_returns_stored_greeting() {
+        testing_env!(get_context(vec![], true));
+        let mut contract = Welcome::default();
+        contract.records.insert("bob_near".to_string(), "howdy".to_string());
+        let bytes = contract.get_greeting_bytes(ValidAccountId::try_from("bob_near").unwrap());
+        assert_eq!(bytes.0, "howdy".as_bytes());
+        assert_eq!(serde_json::to_string(&bytes).unwrap(), "\"aG93ZHk=\"");
+    }
+
+    #[test]
+    fn get_greeting_bytes_defaults_to_hello() {
+        testing_env!(get_context(vec![], true));
+        let contract = Welcome::default();
+        let bytes = contract.get_greeting_bytes(ValidAccountId::try_from("bob_near").unwrap());
+        assert_eq!(bytes.0, "Hello".as_bytes());
+    }
+
+    #[test]
+    fn is_self_registered_queries_own_storage_balance() {
+        testing_env!(get_context(vec![], false));
+        let contract = Welcome::default();
+        contract.is_self_registered(ValidAccountId::try_from("token.near").unwrap());
+        let receipts: Vec<String> = env::created_receipts().iter().map(|r| format!("{:?}", r)).collect();
+        assert_eq!(receipts.len(), 2);
+        assert!(receipts[0].contains("receiver_id: \"token.near\""));
+        assert!(receipts[0].contains(&format!("{:?}", "storage_balance_of".as_bytes())));
+        assert!(receipts[0].contains(&format!("{:?}", "{\"account_id\":\"alice_near\"}".as_bytes())));
+    }
+
+    #[test]
+    fn on_storage_balance_of_registered() {
+        let result = serde_json::to_vec(&Some(StorageBalance { total: U128(125), available: U128(0) })).unwrap();
+        set_context_with_results(get_callback_context(), vec![PromiseResult::Successful(result)]);
+        let contract = Welcome::default();
+        assert!(contract.on_storage_balance_of());
+    }
+
+    #[test]
+    fn on_storage_balance_of_unregistered() {
+        set_context_with_results(get_callback_context(), vec![PromiseResult::Successful(b"null".to_vec())]);
+        let contract = Welcome::default();
+        assert!(!contract.on_storage_balance_of());
+    }
+
+    #[test]
+    fn greetings_root_is_deterministic() {
+        testing_env!(get_context(vec![], true));
+        let mut first = Welcome::default();
+        first.records.insert("bob_near".to_string(), "howdy".to_string());
+        first.records.insert("alice_near".to_string(), "hi".to_string());
+        let mut second = Welcome::default();
+        second.records.insert("alice_near".to_string(), "hi".to_string());
+        second.records.insert("bob_near".to_string(), "howdy".to_string());
+        assert_eq!(first.greetings_root(), second.greetings_root());
+
+        let alice = ("alice_near".to_string(), "hi".to_string()).try_to_vec().unwrap();
+        let bob = ("bob_near".to_string(), "howdy".to_string()).try_to_vec().unwrap();
+        let root = env::sha256(&[vec![0u8; 32], alice].concat());
+        let root = env::sha256(&[root, bob].concat());
+        assert_eq!(first.greetings_root().0, root);
+    }
+
+    #[test]
+    fn greetings_root_changes_with_a_greeting() {
+        testing_env!(get_context(vec![], true));
+        let mut contract = Welcome::default();
+        assert_eq!(contract.greetings_root().0, vec![0u8; 32]);
+        contract.records.insert("bob_near".to_string(), "howdy".to_string());
+        let before = contract.greetings_root();
+        contract.records.insert("bob_near".to_string(), "hello".to_string());
+        assert_ne!(contract.greetings_root(), before);
+    }
 }